@@ -0,0 +1,19 @@
+use diesel::Connection;
+
+// Encrypts any plaintext rows left over in `secrets`. Safe to re-run; rows
+// already encrypted (non-empty `nonce`) are left untouched.
+fn main() {
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let passphrase =
+        std::env::var("KBS_APP_PASSPHRASE").expect("KBS_APP_PASSPHRASE must be set");
+
+    let conn = diesel::SqliteConnection::establish(&database_url)
+        .unwrap_or_else(|e| panic!("failed to connect to {}: {}", database_url, e));
+
+    let app_key = reference_kbs::load_or_init_app_key(&conn, &passphrase)
+        .expect("operator passphrase does not match the stored app key");
+
+    let migrated =
+        reference_kbs::migrate_plaintext_secrets(&conn, &app_key).expect("migration failed");
+    println!("encrypted {} plaintext secret(s)", migrated);
+}
@@ -0,0 +1,73 @@
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::Argon2;
+use rand::RngCore;
+use std::error::Error;
+use std::fmt;
+
+pub const SALT_LEN: usize = 16;
+pub const NONCE_LEN: usize = 12;
+pub const APP_KEY_LEN: usize = 32;
+
+// Encrypted under the app key and stashed alongside its salt so the server
+// can tell "wrong passphrase" from "first boot" without ever touching a
+// real secret.
+const VERIFY_PLAINTEXT: &[u8] = b"reference-kbs-app-key-check";
+
+#[derive(Debug, Clone)]
+pub struct CryptoError(String);
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for CryptoError {}
+
+pub fn random_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+pub fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+// Derives the app-wide key from the operator passphrase and a per-install
+// salt via Argon2id.
+pub fn derive_app_key(passphrase: &str, salt: &[u8]) -> Result<[u8; APP_KEY_LEN], CryptoError> {
+    let mut key = [0u8; APP_KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| CryptoError(e.to_string()))?;
+    Ok(key)
+}
+
+pub fn encrypt(key: &[u8], nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| CryptoError(e.to_string()))?;
+    cipher
+        .encrypt(Nonce::from_slice(nonce), plaintext)
+        .map_err(|e| CryptoError(e.to_string()))
+}
+
+pub fn decrypt(key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| CryptoError(e.to_string()))?;
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|e| CryptoError(e.to_string()))
+}
+
+// Seals the fixed verification plaintext under a freshly derived app key.
+pub fn seal_verification_blob(key: &[u8], nonce: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    encrypt(key, nonce, VERIFY_PLAINTEXT)
+}
+
+// Unseals the stored verification blob; returns false (never an error) on a
+// wrong passphrase.
+pub fn verify_app_key(key: &[u8], nonce: &[u8], verify_blob: &[u8]) -> bool {
+    matches!(decrypt(key, nonce, verify_blob), Ok(pt) if pt == VERIFY_PLAINTEXT)
+}
@@ -1,30 +1,116 @@
+use bincode;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub mod attester;
 use attester::Attester;
+pub mod crypto;
+pub mod secrets_store;
 pub mod sev;
 use crate::sev::SevAttester;
 
-use rocket::http::{Cookie, CookieJar};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::request::{FromRequest, Outcome};
 use rocket::response::status::{BadRequest, Unauthorized};
 use rocket::serde::json::{json, Json, Value};
-use rocket::State;
+use rocket::{Orbit, Request, Rocket, State};
 
 #[macro_use]
 extern crate rocket;
 use rocket::serde::{Deserialize, Serialize};
 
-use kbs_types::{Attestation, Request, SevRequest, Tee};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use kbs_types::{Attestation, Request as KbsRequest, SevRequest, Tee};
 use uuid::Uuid;
 
+pub use secrets_store::{get_secret_store, register_secret_store};
+
 use rocket_sync_db_pools::database;
 
 #[macro_use]
 extern crate diesel;
 
 use diesel::prelude::*;
+use diesel::OptionalExtension;
+
+// A client can keep renewing without re-attesting, up to this ceiling.
+const REFRESH_TOKEN_TTL_SECS: u64 = 24 * 60 * 60;
+
+// A refresh token is distinguished by `typ: "refresh"`; an access token omits
+// `typ` entirely.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Claims {
+    sub: String,
+    workload_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+    iat: usize,
+    exp: usize,
+}
+
+fn now_secs() -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize
+}
+
+fn issue_token(
+    signing_key: &[u8],
+    session_id: &str,
+    workload_id: &str,
+    ttl_secs: u64,
+    typ: Option<&str>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let iat = now_secs();
+    let claims = Claims {
+        sub: session_id.to_string(),
+        workload_id: workload_id.to_string(),
+        typ: typ.map(|t| t.to_string()),
+        iat,
+        exp: iat + ttl_secs as usize,
+    };
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(signing_key),
+    )
+}
+
+fn decode_token(signing_key: &[u8], token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(signing_key),
+        &Validation::new(Algorithm::HS256),
+    )?;
+    Ok(data.claims)
+}
+
+// Falls back to the `session_id` cookie, which post-attestation holds the
+// access token rather than a raw session id.
+pub struct BearerToken(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|t| t.to_string())
+            .or_else(|| req.cookies().get_private("session_id").map(|c| c.value().to_string()));
+
+        match token {
+            Some(t) => Outcome::Success(BearerToken(t)),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize, Queryable, Insertable)]
 #[serde(crate = "rocket::serde")]
@@ -41,7 +127,8 @@ table! {
     }
 }
 
-#[derive(Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
 pub enum SessionStatus {
     Authorized,
     Unauthorized,
@@ -59,13 +146,18 @@ pub struct Session {
 unsafe impl Send for Session {}
 
 impl Session {
-    pub fn new(id: String, workload_id: String, attester: Box<dyn Attester>) -> Session {
+    pub fn new(
+        id: String,
+        workload_id: String,
+        attester: Box<dyn Attester>,
+        ttl_secs: u64,
+    ) -> Session {
         Session {
             id,
             workload_id,
             attester,
             status: SessionStatus::Unauthorized,
-            expires_on: Instant::now() + Duration::from_secs(3 * 60 * 60),
+            expires_on: Instant::now() + Duration::from_secs(ttl_secs),
         }
     }
 
@@ -82,22 +174,198 @@ impl Session {
     }
 
     pub fn is_valid(&self) -> bool {
-        if self.status != SessionStatus::Authorized {
-            println!("Session is not authorized");
-        }
-        if Instant::now() > self.expires_on {
-            println!("Session expired");
-        }
         self.status == SessionStatus::Authorized && Instant::now() < self.expires_on
     }
 
     pub fn approve(&mut self) {
         self.status = SessionStatus::Authorized;
     }
+
+    pub fn extend(&mut self, ttl_secs: u64) {
+        self.expires_on = Instant::now() + Duration::from_secs(ttl_secs);
+    }
 }
 
+// The access JWT carries the session id and its own expiry; `sessions` holds
+// the per-session attester (with the TEE `session_verified` material
+// `encrypt_secret` needs) plus its status and expiry, keyed by the
+// `session_id` carried in the access JWT's `sub` claim. `key` requires both a
+// valid token and an approved, unexpired in-memory session. Wrapped in an
+// `Arc` so the background expiry sweeper can hold its own handle.
 pub struct SessionState {
-    pub sessions: RwLock<HashMap<String, Arc<Mutex<Session>>>>,
+    pub sessions: Arc<RwLock<HashMap<String, Arc<Mutex<Session>>>>>,
+    pub signing_key: Vec<u8>,
+    pub app_key: [u8; crypto::APP_KEY_LEN],
+    pub session_ttl_secs: u64,
+}
+
+// Durable mirror of a session's authorization status, so a `key` call
+// against a session missing from the in-memory map can be told to
+// re-attest rather than treated as unknown.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SessionMetadata {
+    status: SessionStatus,
+    expires_on_unix: u64,
+}
+
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset)]
+#[table_name = "session_records"]
+struct SessionRecord {
+    id: String,
+    workload_id: String,
+    // bincode-encoded `SessionMetadata`.
+    metadata: Vec<u8>,
+}
+
+table! {
+    session_records (id) {
+        id -> Text,
+        workload_id -> Text,
+        metadata -> Binary,
+    }
+}
+
+// Persists (inserts or updates) the durable record for a session.
+fn persist_session_record(
+    conn: &diesel::SqliteConnection,
+    id: &str,
+    workload_id: &str,
+    status: SessionStatus,
+    expires_on_unix: u64,
+) -> Result<(), diesel::result::Error> {
+    let metadata = bincode::serialize(&SessionMetadata {
+        status,
+        expires_on_unix,
+    })
+    .expect("SessionMetadata is always serializable");
+
+    let record = SessionRecord {
+        id: id.to_string(),
+        workload_id: workload_id.to_string(),
+        metadata,
+    };
+
+    diesel::replace_into(session_records::table)
+        .values(&record)
+        .execute(conn)?;
+    Ok(())
+}
+
+// Background fairing that periodically evicts expired sessions from both
+// the in-memory map and the durable `session_records` table.
+pub struct SessionSweeper {
+    pub sessions: Arc<RwLock<HashMap<String, Arc<Mutex<Session>>>>>,
+    pub database_url: String,
+    pub interval_secs: u64,
+}
+
+#[rocket::async_trait]
+impl Fairing for SessionSweeper {
+    fn info(&self) -> Info {
+        Info {
+            name: "session expiry sweeper",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, _rocket: &Rocket<Orbit>) {
+        let sessions = self.sessions.clone();
+        let database_url = self.database_url.clone();
+        let interval_secs = self.interval_secs;
+
+        rocket::tokio::spawn(async move {
+            let mut ticker = rocket::tokio::time::interval(Duration::from_secs(interval_secs));
+            loop {
+                ticker.tick().await;
+                let sessions = sessions.clone();
+                let database_url = database_url.clone();
+                // The Diesel calls in here are blocking, so run them on the
+                // blocking pool rather than tying up a Tokio worker thread.
+                let _ = rocket::tokio::task::spawn_blocking(move || {
+                    evict_expired_sessions(&sessions, &database_url);
+                })
+                .await;
+            }
+        });
+    }
+}
+
+fn evict_expired_sessions(
+    sessions: &RwLock<HashMap<String, Arc<Mutex<Session>>>>,
+    database_url: &str,
+) {
+    let now = Instant::now();
+    let expired: Vec<String> = sessions
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, s)| now > s.lock().unwrap().expires_on)
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    if !expired.is_empty() {
+        let mut map = sessions.write().unwrap();
+        for id in &expired {
+            map.remove(id);
+        }
+    }
+
+    let conn = match diesel::SqliteConnection::establish(database_url) {
+        Ok(conn) => conn,
+        Err(e) => {
+            println!(
+                "session sweeper: failed to connect to {}: {}",
+                database_url, e
+            );
+            return;
+        }
+    };
+
+    // The in-memory map only ever holds sessions this process attested; a
+    // restart (or another process) can leave rows behind that never show up
+    // there, so expiry here is judged from each row's own metadata rather
+    // than map membership.
+    let records: Vec<SessionRecord> = match session_records::table.load(&conn) {
+        Ok(records) => records,
+        Err(e) => {
+            println!("session sweeper: failed to load session records: {}", e);
+            return;
+        }
+    };
+
+    let now_unix = now_secs() as u64;
+    let expired_ids: Vec<String> = records
+        .into_iter()
+        .filter(
+            |r| match bincode::deserialize::<SessionMetadata>(&r.metadata) {
+                Ok(metadata) => metadata.expires_on_unix < now_unix,
+                Err(_) => true,
+            },
+        )
+        .map(|r| r.id)
+        .collect();
+
+    if expired_ids.is_empty() {
+        return;
+    }
+
+    let count = expired_ids.len();
+    if let Err(e) =
+        diesel::delete(session_records::table.filter(session_records::id.eq_any(expired_ids)))
+            .execute(&conn)
+    {
+        println!(
+            "session sweeper: failed to evict {} expired records: {}",
+            count, e
+        );
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RefreshRequest {
+    refresh_token: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Queryable, Insertable)]
@@ -120,19 +388,106 @@ table! {
 #[table_name = "secrets"]
 struct Secret {
     key_id: String,
+    // Hex-encoded AES-256-GCM ciphertext; a row with an empty `nonce` still
+    // holds plaintext until `migrate_plaintext_secrets` encrypts it.
     secret: String,
+    // Hex-encoded per-row nonce the ciphertext was sealed under.
+    nonce: String,
 }
 
 table! {
     secrets (key_id) {
         key_id -> Text,
         secret -> Text,
+        nonce -> Text,
+    }
+}
+
+// Single-row table holding the material needed to confirm the operator
+// passphrase still derives the app key that was used to seal `secrets`.
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "kv"]
+struct AppKeyVerifier {
+    salt: Vec<u8>,
+    verify_nonce: Vec<u8>,
+    verify_blob: Vec<u8>,
+}
+
+table! {
+    kv (salt) {
+        salt -> Binary,
+        verify_nonce -> Binary,
+        verify_blob -> Binary,
     }
 }
 
 #[database("diesel")]
 pub struct Db(diesel::SqliteConnection);
 
+// Derives the app-wide encryption key from the operator passphrase,
+// provisioning the `kv` verification row on first boot and rejecting a
+// passphrase that doesn't match it on every later boot.
+pub fn load_or_init_app_key(
+    conn: &diesel::SqliteConnection,
+    passphrase: &str,
+) -> Result<[u8; crypto::APP_KEY_LEN], String> {
+    match kv::table.first::<AppKeyVerifier>(conn) {
+        Ok(row) => {
+            let key =
+                crypto::derive_app_key(passphrase, &row.salt).map_err(|e| e.to_string())?;
+            if crypto::verify_app_key(&key, &row.verify_nonce, &row.verify_blob) {
+                Ok(key)
+            } else {
+                Err("operator passphrase does not match the stored app key".to_string())
+            }
+        }
+        Err(diesel::result::Error::NotFound) => {
+            let salt = crypto::random_salt();
+            let key = crypto::derive_app_key(passphrase, &salt).map_err(|e| e.to_string())?;
+            let verify_nonce = crypto::random_nonce();
+            let verify_blob =
+                crypto::seal_verification_blob(&key, &verify_nonce).map_err(|e| e.to_string())?;
+            diesel::insert_into(kv::table)
+                .values(AppKeyVerifier {
+                    salt: salt.to_vec(),
+                    verify_nonce: verify_nonce.to_vec(),
+                    verify_blob,
+                })
+                .execute(conn)
+                .map_err(|e| e.to_string())?;
+            Ok(key)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// One-shot migration: encrypts any `secrets` rows still holding plaintext
+// (identified by an empty `nonce`) under the current app key.
+pub fn migrate_plaintext_secrets(
+    conn: &diesel::SqliteConnection,
+    app_key: &[u8],
+) -> Result<usize, String> {
+    let plaintext_rows: Vec<Secret> = secrets::table
+        .filter(secrets::nonce.eq(""))
+        .load(conn)
+        .map_err(|e| e.to_string())?;
+
+    let count = plaintext_rows.len();
+    for row in plaintext_rows {
+        let nonce = crypto::random_nonce();
+        let ciphertext = crypto::encrypt(app_key, &nonce, row.secret.as_bytes())
+            .map_err(|e| e.to_string())?;
+        diesel::update(secrets::table.filter(secrets::key_id.eq(&row.key_id)))
+            .set((
+                secrets::secret.eq(hex::encode(ciphertext)),
+                secrets::nonce.eq(hex::encode(nonce)),
+            ))
+            .execute(conn)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(count)
+}
+
 #[get("/")]
 pub fn index() -> Result<String, Unauthorized<String>> {
     Err(Unauthorized(None))
@@ -143,7 +498,7 @@ pub async fn auth(
     db: Db,
     state: &State<SessionState>,
     cookies: &CookieJar<'_>,
-    request: Json<Request>,
+    request: Json<KbsRequest>,
 ) -> Result<Value, BadRequest<String>> {
     let session_id = Uuid::new_v4().to_simple().to_string();
 
@@ -179,8 +534,14 @@ pub async fn auth(
         .challenge()
         .map_err(|e| BadRequest(Some(e.to_string())))?;
 
-    let session = Session::new(session_id, request.workload_id.clone(), attester);
-    cookies.add(Cookie::new("session_id", session.id()));
+    let session = Session::new(
+        session_id,
+        request.workload_id.clone(),
+        attester,
+        state.session_ttl_secs,
+    );
+    // Private jar: encrypted + MAC'd, so the client can't read or forge it.
+    cookies.add_private(Cookie::new("session_id", session.id()));
 
     state
         .sessions
@@ -196,11 +557,13 @@ pub async fn attest(
     state: &State<SessionState>,
     cookies: &CookieJar<'_>,
     attestation: Json<Attestation>,
-) -> Result<(), BadRequest<String>> {
+) -> Result<Value, BadRequest<String>> {
     let session_id = cookies
-        .get("session_id")
+        .get_private("session_id")
         .ok_or_else(|| BadRequest(Some("Missing cookie".to_string())))?
-        .value();
+        .value()
+        .to_string();
+    let session_id = session_id.as_str();
 
     // We're just cloning an Arc, looks like a false positive to me...
     #[allow(clippy::significant_drop_in_scrutinee)]
@@ -227,26 +590,148 @@ pub async fn attest(
         .map_err(|e| BadRequest(Some(e.to_string())))?;
     session.approve();
 
-    Ok(())
+    let session_id = session.id();
+    let workload_id = session.workload_id();
+    drop(session);
+
+    // Mirror the now-authorized session into the durable table.
+    let expires_on_unix = now_secs() as u64 + state.session_ttl_secs;
+    let record_id = session_id.clone();
+    let record_workload_id = workload_id.clone();
+    db.run(move |conn| {
+        persist_session_record(
+            conn,
+            &record_id,
+            &record_workload_id,
+            SessionStatus::Authorized,
+            expires_on_unix,
+        )
+    })
+    .await
+    .map_err(|e| BadRequest(Some(e.to_string())))?;
+
+    let access_token = issue_token(
+        &state.signing_key,
+        &session_id,
+        &workload_id,
+        state.session_ttl_secs,
+        None,
+    )
+    .map_err(|e| BadRequest(Some(e.to_string())))?;
+    let refresh_token = issue_token(
+        &state.signing_key,
+        &session_id,
+        &workload_id,
+        REFRESH_TOKEN_TTL_SECS,
+        Some("refresh"),
+    )
+    .map_err(|e| BadRequest(Some(e.to_string())))?;
+
+    // The cookie now carries the access token itself, not a raw session id.
+    cookies.add_private(Cookie::new("session_id", access_token.clone()));
+
+    Ok(json!({
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+    }))
+}
+
+#[post("/refresh", format = "application/json", data = "<request>")]
+pub async fn refresh(
+    db: Db,
+    state: &State<SessionState>,
+    request: Json<RefreshRequest>,
+) -> Result<Value, Unauthorized<String>> {
+    let claims = decode_token(&state.signing_key, &request.refresh_token)
+        .map_err(|e| Unauthorized(Some(e.to_string())))?;
+
+    if claims.typ.as_deref() != Some("refresh") {
+        return Err(Unauthorized(Some("Not a refresh token".to_string())));
+    }
+
+    // Never touches the TEE session state, only its expiry, so a refresh
+    // alone can't grant `encrypt_secret` access.
+    if let Some(session_lock) = state.sessions.read().unwrap().get(&claims.sub).cloned() {
+        session_lock.lock().unwrap().extend(state.session_ttl_secs);
+    }
+
+    let expires_on_unix = now_secs() as u64 + state.session_ttl_secs;
+    let record_id = claims.sub.clone();
+    let record_workload_id = claims.workload_id.clone();
+    db.run(move |conn| {
+        persist_session_record(
+            conn,
+            &record_id,
+            &record_workload_id,
+            SessionStatus::Authorized,
+            expires_on_unix,
+        )
+    })
+    .await
+    .map_err(|e| Unauthorized(Some(e.to_string())))?;
+
+    let access_token = issue_token(
+        &state.signing_key,
+        &claims.sub,
+        &claims.workload_id,
+        state.session_ttl_secs,
+        None,
+    )
+    .map_err(|e| Unauthorized(Some(e.to_string())))?;
+
+    Ok(json!({ "access_token": access_token }))
 }
 
 #[get("/key/<key_id>")]
 pub async fn key(
     db: Db,
     state: &State<SessionState>,
-    cookies: &CookieJar<'_>,
+    token: BearerToken,
     key_id: &str,
 ) -> Result<Value, Unauthorized<String>> {
-    let session_id = cookies
-        .get("session_id")
-        .ok_or_else(|| Unauthorized(Some("Missing cookie".to_string())))?
-        .value();
+    let claims = decode_token(&state.signing_key, &token.0)
+        .map_err(|e| Unauthorized(Some(e.to_string())))?;
+
+    if claims.typ.is_some() {
+        return Err(Unauthorized(Some("Invalid session".to_string())));
+    }
 
     // We're just cloning an Arc, looks like a false positive to me...
     #[allow(clippy::significant_drop_in_scrutinee)]
-    let session_lock = match state.sessions.read().unwrap().get(session_id) {
-        Some(s) => s.clone(),
-        None => return Err(Unauthorized(Some("Invalid cookie".to_string()))),
+    let found = state.sessions.read().unwrap().get(&claims.sub).cloned();
+    let session_lock = match found {
+        Some(s) => s,
+        None => {
+            // The TEE `session_verified` material doesn't survive a
+            // restart even though the durable record does; tell a
+            // recognised-but-missing session to re-attest rather than
+            // reporting it as unknown.
+            let session_id = claims.sub.clone();
+            let record: Option<SessionRecord> = db
+                .run(move |conn| {
+                    session_records::table
+                        .filter(session_records::id.eq(session_id))
+                        .first(conn)
+                        .optional()
+                })
+                .await
+                .map_err(|e| Unauthorized(Some(e.to_string())))?;
+
+            let metadata =
+                record.and_then(|r| bincode::deserialize::<SessionMetadata>(&r.metadata).ok());
+
+            return match metadata {
+                Some(metadata)
+                    if metadata.status == SessionStatus::Authorized
+                        && now_secs() as u64 <= metadata.expires_on_unix =>
+                {
+                    Err(Unauthorized(Some(
+                        "Session requires re-attestation after restart".to_string(),
+                    )))
+                }
+                _ => Err(Unauthorized(Some("Unknown session".to_string()))),
+            };
+        }
     };
 
     if !session_lock.lock().unwrap().is_valid() {
@@ -263,10 +748,15 @@ pub async fn key(
         .await
         .map_err(|e| Unauthorized(Some(e.to_string())))?;
 
+    let nonce =
+        hex::decode(&secrets_entry.nonce).map_err(|e| Unauthorized(Some(e.to_string())))?;
+    let ciphertext =
+        hex::decode(&secrets_entry.secret).map_err(|e| Unauthorized(Some(e.to_string())))?;
+    let mut plaintext = crypto::decrypt(&state.app_key, &nonce, &ciphertext)
+        .map_err(|e| Unauthorized(Some(e.to_string())))?;
+
     let mut session = session_lock.lock().unwrap();
-    let secret = session
-        .attester()
-        .encrypt_secret(secrets_entry.secret.as_bytes())
-        .unwrap();
+    let secret = session.attester().encrypt_secret(&plaintext).unwrap();
+    plaintext.iter_mut().for_each(|b| *b = 0);
     Ok(secret)
 }
@@ -1,144 +1,81 @@
 #![feature(option_result_contains)]
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, RwLock};
 
 #[macro_use]
 extern crate rocket;
-use rocket::http::{Cookie, CookieJar};
-use rocket::response::status::{BadRequest, Unauthorized};
-use rocket::serde::json::{json, Json, Value};
-use rocket::serde::{Deserialize, Serialize};
-use rocket::State;
 
-use kbs_types::{Attestation, Request, SevRequest, Tee};
-use uuid::Uuid;
+use diesel::Connection;
 
-use reference_kbs::attester::Attester;
-use reference_kbs::secrets_store::SecretStore;
-use reference_kbs::sev::SevAttester;
-use reference_kbs::{get_secret_store, key, register_secret_store, Session, SessionState};
+use reference_kbs::secrets_store::{admin_login, AdminConfig};
+use reference_kbs::{
+    attest, auth, get_secret_store, index, key, load_or_init_app_key, refresh,
+    register_secret_store, Db, SessionSweeper, SessionState,
+};
 
-use rocket_sync_db_pools::database;
+const DEFAULT_SESSION_TTL_SECS: u64 = 3 * 60 * 60;
+const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 60;
 
-//use std::thread;
-
-#[macro_use]
-extern crate diesel;
-
-use diesel::prelude::*;
-
-#[derive(Debug, Clone, Deserialize, Serialize, Queryable, Insertable)]
-#[serde(crate = "rocket::serde")]
-#[table_name = "measurements"]
-struct Measurement {
-    workload_id: String,
-    launch_measurement: String,
-}
-
-table! {
-    measurements (workload_id) {
-        workload_id -> Text,
-        launch_measurement -> Text,
-    }
-}
-
-#[database("diesel")]
-struct Db(diesel::SqliteConnection);
-
-#[get("/")]
-fn index() -> Result<String, Unauthorized<String>> {
-    //Ok("Hello, world!".to_string())
-    Err(Unauthorized(None))
-}
-
-#[post("/auth", format = "application/json", data = "<request>")]
-fn auth(
-    state: &State<SessionState>,
-    cookies: &CookieJar<'_>,
-    request: Json<Request>,
-) -> Result<Value, BadRequest<String>> {
-    let session_id = Uuid::new_v4().to_simple().to_string();
-
-    let mut attester: Box<dyn Attester> = match request.tee {
-        Tee::Sev => {
-            let sev_request: SevRequest = serde_json::from_str(&request.extra_params)
-                .map_err(|e| BadRequest(Some(e.to_string())))?;
-            Box::new(SevAttester::new(
-                session_id.clone(),
-                request.workload_id.clone(),
-                sev_request.build,
-                sev_request.chain,
-            )) as Box<dyn Attester>
-        }
-        _ => return Err(BadRequest(Some("Unsupported TEE".to_string()))),
-    };
-
-    let challenge = attester
-        .challenge()
-        .map_err(|e| BadRequest(Some(e.to_string())))?;
-
-    let session = Session::new(session_id, request.workload_id.clone(), attester);
-    cookies.add(Cookie::new("session_id", session.id()));
-
-    state
-        .sessions
-        .write()
-        .unwrap()
-        .insert(session.id(), Arc::new(Mutex::new(session)));
-    Ok(json!(challenge))
-}
-
-#[post("/attest", format = "application/json", data = "<attestation>")]
-async fn attest(
-    db: Db,
-    state: &State<SessionState>,
-    cookies: &CookieJar<'_>,
-    attestation: Json<Attestation>,
-) -> Result<(), BadRequest<String>> {
-    let session_id = cookies
-        .get("session_id")
-        .ok_or_else(|| BadRequest(Some("Missing cookie".to_string())))?
-        .value();
-    // We're just cloning an Arc, looks like a false positive to me...
-    #[allow(clippy::significant_drop_in_scrutinee)]
-    let session_lock = match state.sessions.read().unwrap().get(session_id) {
-        Some(s) => s.clone(),
-        None => return Err(BadRequest(Some("Invalid cookie".to_string()))),
-    };
-
-    let workload_id = session_lock.lock().unwrap().workload_id();
-
-    let measurement_entry: Measurement = db
-        .run(move |conn| {
-            measurements::table
-                .filter(measurements::workload_id.eq(workload_id))
-                .first(conn)
-        })
-        .await
-        .map_err(|e| BadRequest(Some(e.to_string())))?;
-
-    let mut session = session_lock.lock().unwrap();
-    session
-        .attester()
-        .attest(&attestation, &measurement_entry.launch_measurement)
-        .map_err(|e| BadRequest(Some(e.to_string())))?;
-    session.approve();
-
-    Ok(())
+fn env_u64_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
 }
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
-        .mount("/kbs/v0", routes![index, auth, attest, key])
+    let signing_key = std::env::var("KBS_JWT_SECRET")
+        .expect("KBS_JWT_SECRET must be set")
+        .into_bytes();
+
+    let admin_config = AdminConfig {
+        jwt_secret: std::env::var("KBS_ADMIN_JWT_SECRET")
+            .expect("KBS_ADMIN_JWT_SECRET must be set")
+            .into_bytes(),
+        username: std::env::var("KBS_ADMIN_USERNAME").expect("KBS_ADMIN_USERNAME must be set"),
+        password_hash: std::env::var("KBS_ADMIN_PASSWORD_HASH")
+            .expect("KBS_ADMIN_PASSWORD_HASH must be set"),
+    };
+
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let passphrase =
+        std::env::var("KBS_APP_PASSPHRASE").expect("KBS_APP_PASSPHRASE must be set");
+    let conn = diesel::SqliteConnection::establish(&database_url)
+        .unwrap_or_else(|e| panic!("failed to connect to {}: {}", database_url, e));
+    let app_key = load_or_init_app_key(&conn, &passphrase)
+        .expect("operator passphrase does not match the stored app key");
+    drop(conn);
+
+    // Backs the private `session_id` cookie jar; must stay stable across
+    // restarts or every cookie invalidates.
+    let cookie_secret_key =
+        std::env::var("KBS_COOKIE_SECRET_KEY").expect("KBS_COOKIE_SECRET_KEY must be set");
+    let config = rocket::Config::figment().merge(("secret_key", cookie_secret_key));
+
+    let session_ttl_secs = env_u64_or("KBS_SESSION_TTL_SECS", DEFAULT_SESSION_TTL_SECS);
+    let sweep_interval_secs =
+        env_u64_or("KBS_SESSION_SWEEP_INTERVAL_SECS", DEFAULT_SWEEP_INTERVAL_SECS);
+    let sessions = Arc::new(RwLock::new(HashMap::new()));
+
+    rocket::custom(config)
+        .mount("/kbs/v0", routes![index, auth, attest, refresh, key])
         .mount(
             "/secret-store",
-            routes![register_secret_store, get_secret_store],
+            routes![register_secret_store, get_secret_store, admin_login],
         )
         .manage(SessionState {
-            sessions: RwLock::new(HashMap::new()),
-            secret_store: RwLock::new(SecretStore::new("http://127.0.0.1:8200", "myroot")),
+            sessions: sessions.clone(),
+            signing_key,
+            app_key,
+            session_ttl_secs,
         })
+        .manage(admin_config)
         .attach(Db::fairing())
+        .attach(SessionSweeper {
+            sessions,
+            database_url,
+            interval_secs: sweep_interval_secs,
+        })
 }
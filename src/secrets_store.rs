@@ -1,12 +1,19 @@
 use rocket::{get, post};
 
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use bincode;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use lazy_static::lazy_static;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status::Unauthorized;
 use rocket::serde::json::{json, Json, Value};
 use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
 use std::error::Error;
 use std::fmt;
 use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
 use vaultrs::kv2;
 
@@ -14,6 +21,106 @@ lazy_static! {
     static ref SECRET_STORE: RwLock<SecretStore> = RwLock::new(SecretStore::default());
 }
 
+const ADMIN_TOKEN_TTL_SECS: u64 = 60 * 60;
+
+pub struct AdminConfig {
+    pub jwt_secret: Vec<u8>,
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AdminClaims {
+    sub: String,
+    role: String,
+    iat: usize,
+    exp: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminLogin {
+    username: String,
+    password: String,
+}
+
+// Gates the secret-store management routes, which otherwise let anyone
+// repoint the Vault URL/token.
+pub struct AdminAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAuth {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match req.guard::<&rocket::State<AdminConfig>>().await {
+            Outcome::Success(c) => c,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let token = match req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        {
+            Some(t) => t,
+            None => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let claims = match decode::<AdminClaims>(
+            token,
+            &DecodingKey::from_secret(&config.jwt_secret),
+            &Validation::new(Algorithm::HS256),
+        ) {
+            Ok(data) => data.claims,
+            Err(_) => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        if claims.role == "admin" {
+            Outcome::Success(AdminAuth)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+#[post("/login", format = "json", data = "<login>")]
+pub fn admin_login(
+    config: &rocket::State<AdminConfig>,
+    login: Json<AdminLogin>,
+) -> Result<Value, Unauthorized<String>> {
+    let parsed_hash =
+        PasswordHash::new(&config.password_hash).map_err(|e| Unauthorized(Some(e.to_string())))?;
+
+    let credentials_ok = login.username == config.username
+        && Argon2::default()
+            .verify_password(login.password.as_bytes(), &parsed_hash)
+            .is_ok();
+    if !credentials_ok {
+        return Err(Unauthorized(Some("invalid credentials".to_string())));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize;
+    let claims = AdminClaims {
+        sub: login.username.clone(),
+        role: "admin".to_string(),
+        iat: now,
+        exp: now + ADMIN_TOKEN_TTL_SECS as usize,
+    };
+    let token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(&config.jwt_secret),
+    )
+    .map_err(|e| Unauthorized(Some(e.to_string())))?;
+
+    Ok(json!({ "access_token": token }))
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct Secret {
@@ -103,12 +210,12 @@ fn read_secret_store() -> SecretStore {
 }
 
 #[get("/get")]
-pub fn get_secret_store() -> Json<SecretStore> {
+pub fn get_secret_store(_admin: AdminAuth) -> Json<SecretStore> {
     Json(read_secret_store())
 }
 
 #[post("/update", format = "json", data = "<store>")]
-pub fn register_secret_store(store: Json<SecretStore>) -> Value {
+pub fn register_secret_store(_admin: AdminAuth, store: Json<SecretStore>) -> Value {
     let valid = store.validate();
     match valid {
         Ok(_) => {
@@ -121,8 +228,46 @@ pub fn register_secret_store(store: Json<SecretStore>) -> Value {
     }
 }
 
+// Admin-JWT fixtures shared between this module's unit tests and the
+// integration tests in `tests/secret_store.rs`, which can't see `#[cfg(test)]`
+// items across the crate boundary.
+#[doc(hidden)]
+pub mod test_support {
+    use super::{AdminClaims, AdminConfig, ADMIN_TOKEN_TTL_SECS};
+
+    pub const TEST_ADMIN_JWT_SECRET: &[u8] = b"test-admin-secret";
+
+    pub fn test_admin_config() -> AdminConfig {
+        AdminConfig {
+            jwt_secret: TEST_ADMIN_JWT_SECRET.to_vec(),
+            username: "test-admin".to_string(),
+            password_hash: String::new(),
+        }
+    }
+
+    pub fn test_admin_token() -> String {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as usize;
+        let claims = AdminClaims {
+            sub: "test-admin".to_string(),
+            role: "admin".to_string(),
+            iat: now,
+            exp: now + ADMIN_TOKEN_TTL_SECS as usize,
+        };
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(TEST_ADMIN_JWT_SECRET),
+        )
+        .unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::test_support::{test_admin_config, test_admin_token};
     use super::*;
     use rocket::http::{ContentType, Status};
     use rocket::local::blocking::Client;
@@ -135,15 +280,26 @@ mod tests {
     fn set_secret_store() {
         let store = SecretStore::new("http://127.0.0.1:8200", "sfjdksjfksjfkdjskfjskfjd");
         let serialized_store = serde_json::to_string(&store).unwrap();
-        let rocket = rocket::build().mount("/", routes![register_secret_store, get_secret_store]);
+        let rocket = rocket::build()
+            .mount("/", routes![register_secret_store, get_secret_store])
+            .manage(test_admin_config());
         let client = Client::new(rocket).expect("valid rocket instance");
+        let auth_header = rocket::http::Header::new(
+            "Authorization",
+            format!("Bearer {}", test_admin_token()),
+        );
         let mut response = client
             .post("/update")
             .header(ContentType::JSON)
+            .header(auth_header.clone())
             .body(serialized_store.clone())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
-        let mut response = client.get("/get").header(ContentType::JSON).dispatch();
+        let mut response = client
+            .get("/get")
+            .header(ContentType::JSON)
+            .header(auth_header)
+            .dispatch();
         assert_eq!(response.status(), Status::Ok);
         assert_eq!(response.into_string(), serialized_store.into());
     }
@@ -3,10 +3,12 @@ use rocket::http::{ContentType, Status};
 use rocket::local::blocking::Client;
 use rocket::routes;
 
+use diesel::prelude::*;
+
 use reference_kbs::attester::AttesterError;
+use reference_kbs::secrets_store::test_support::{test_admin_config, test_admin_token};
 use reference_kbs::secrets_store::{get_secret_from_vault, SecretStore};
-use reference_kbs::{get_secret_store, key, register_secret_store, Session, SessionState};
-use rocket::http::{Cookie, CookieJar};
+use reference_kbs::{get_secret_store, key, register_secret_store, Db, Session, SessionState};
 use rocket::serde::json::{json, Value};
 use serde_json::Result;
 use std::collections::HashMap;
@@ -14,6 +16,67 @@ use std::env;
 use std::str;
 use std::sync::{Arc, Mutex, RwLock};
 
+// Seeds a fresh sqlite file with an encrypted `secrets` row for `key_id`, the
+// way `migrate_plaintext_secrets` would have left it, since this tree has no
+// migrations to run against an in-memory db.
+fn seed_secrets_db(key_id: &str, plaintext: &[u8]) -> String {
+    let db_url = format!("/tmp/reference_kbs_test_key_{}.sqlite", std::process::id());
+    let _ = std::fs::remove_file(&db_url);
+
+    let conn = diesel::SqliteConnection::establish(&db_url).expect("failed to create test db");
+    diesel::sql_query(
+        "CREATE TABLE secrets (key_id TEXT NOT NULL PRIMARY KEY, secret TEXT NOT NULL, nonce TEXT NOT NULL)",
+    )
+    .execute(&conn)
+    .unwrap();
+
+    let nonce = reference_kbs::crypto::random_nonce();
+    let ciphertext = reference_kbs::crypto::encrypt(&TEST_APP_KEY, &nonce, plaintext).unwrap();
+    diesel::sql_query("INSERT INTO secrets (key_id, secret, nonce) VALUES (?, ?, ?)")
+        .bind::<diesel::sql_types::Text, _>(key_id)
+        .bind::<diesel::sql_types::Text, _>(hex::encode(ciphertext))
+        .bind::<diesel::sql_types::Text, _>(hex::encode(nonce))
+        .execute(&conn)
+        .unwrap();
+
+    db_url
+}
+
+const TEST_SIGNING_KEY: &[u8] = b"test-signing-key";
+const TEST_APP_KEY: [u8; reference_kbs::crypto::APP_KEY_LEN] =
+    [0u8; reference_kbs::crypto::APP_KEY_LEN];
+
+fn test_access_token(session_id: &str, workload_id: &str) -> String {
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use rocket::serde::Serialize;
+
+    #[derive(Serialize)]
+    #[serde(crate = "rocket::serde")]
+    struct Claims {
+        sub: String,
+        workload_id: String,
+        iat: usize,
+        exp: usize,
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize;
+    let claims = Claims {
+        sub: session_id.to_string(),
+        workload_id: workload_id.to_string(),
+        iat: now,
+        exp: now + 3 * 60 * 60,
+    };
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(TEST_SIGNING_KEY),
+    )
+    .unwrap()
+}
+
 #[actix_rt::test]
 async fn get_secret() {
     let url = env::var("VAULT_ADDR").unwrap();
@@ -31,20 +94,30 @@ fn update_secret_store() {
     let serialized_store =
         serde_json::to_string(&SecretStore::new("http://127.0.0.1:8200", "myroot")).unwrap();
     let state = SessionState {
-        sessions: RwLock::new(HashMap::new()),
-        secret_store: RwLock::new(SecretStore::new("http://127.0.0.1:8200", "myroot")),
+        sessions: Arc::new(RwLock::new(HashMap::new())),
+        signing_key: TEST_SIGNING_KEY.to_vec(),
+        app_key: TEST_APP_KEY,
+        session_ttl_secs: 3 * 60 * 60,
     };
     let rocket = rocket::build()
         .mount("/", routes![register_secret_store, get_secret_store])
-        .manage(state);
+        .manage(state)
+        .manage(test_admin_config());
     let client = Client::new(rocket).expect("valid rocket instance");
+    let auth_header =
+        rocket::http::Header::new("Authorization", format!("Bearer {}", test_admin_token()));
     let mut response = client
         .post("/update")
         .header(ContentType::JSON)
+        .header(auth_header.clone())
         .body(serialized_store.clone())
         .dispatch();
     assert_eq!(response.status(), Status::Ok);
-    let mut response = client.get("/get").header(ContentType::JSON).dispatch();
+    let mut response = client
+        .get("/get")
+        .header(ContentType::JSON)
+        .header(auth_header)
+        .dispatch();
     assert_eq!(response.status(), Status::Ok);
     assert_eq!(response.into_string(), serialized_store.into());
 }
@@ -57,13 +130,16 @@ fn test_key() {
         .expect_encrypt_secret()
         .returning(|x| Ok(json!(str::from_utf8(&x).unwrap())));
     let state = SessionState {
-        sessions: RwLock::new(HashMap::new()),
-        secret_store: RwLock::new(SecretStore::new("http://127.0.0.1:8200", "myroot")),
+        sessions: Arc::new(RwLock::new(HashMap::new())),
+        signing_key: TEST_SIGNING_KEY.to_vec(),
+        app_key: TEST_APP_KEY,
+        session_ttl_secs: 3 * 60 * 60,
     };
     let mut session = Session::new(
         "test-session".to_string(),
         "fakeid".to_string(),
         Box::new(mockAttester),
+        3 * 60 * 60,
     );
     session.approve();
     state
@@ -71,12 +147,22 @@ fn test_key() {
         .write()
         .unwrap()
         .insert("test-session".to_string(), Arc::new(Mutex::new(session)));
-    let rocket = rocket::build().mount("/", routes![key]).manage(state);
+    let db_url = seed_secrets_db("fakeid", b"test");
+    let figment = rocket::Config::figment().merge(("databases.diesel.url", db_url.clone()));
+    let rocket = rocket::custom(figment)
+        .mount("/", routes![key])
+        .manage(state)
+        .attach(Db::fairing());
     let client = Client::new(rocket).expect("valid rocket instance");
+    let access_token = test_access_token("test-session", "fakeid");
     let response = client
         .get("/key/fakeid")
-        .cookie(Cookie::new("session_id", "test-session"))
+        .header(rocket::http::Header::new(
+            "Authorization",
+            format!("Bearer {}", access_token),
+        ))
         .dispatch();
     assert_eq!(response.status(), Status::Ok);
     assert_eq!(response.into_string().unwrap().contains("test"), true);
+    let _ = std::fs::remove_file(&db_url);
 }